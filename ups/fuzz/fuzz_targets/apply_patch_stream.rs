@@ -0,0 +1,16 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+
+// CRC checks are disabled so the fuzzer spends its budget on the
+// parsing/application logic instead of on guessing valid checksums.
+fuzz_target!(|data: (Vec<u8>, Vec<u8>)| {
+    let (source, patch) = data;
+    let mut out = Vec::new();
+    let _ = ups::apply_patch_stream(
+        ups::Options { skip_crc: true },
+        std::io::Cursor::new(source),
+        &patch,
+        &mut out,
+    );
+});
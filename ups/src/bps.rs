@@ -0,0 +1,303 @@
+//! Support for the `BPS1` patch format, a sibling of UPS with a smarter delta
+//! model (source/target copies with persistent relative pointers instead of
+//! a single XOR stream).
+
+use alloc::vec::Vec;
+
+use crate::{read_vuint, verify_crc, Options};
+
+#[derive(Debug)]
+pub enum Error {
+    MissingHeader,
+    MalformedPatch,
+    CrcMismatchOriginal,
+    CrcMismatchPatch,
+    CrcMismatchTarget,
+}
+
+impl core::fmt::Display for Error {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.write_str(match self {
+            Error::MissingHeader => "Missing 'BPS1' header at start of patch",
+            Error::MalformedPatch => "Input patch malformed",
+            Error::CrcMismatchOriginal => "CRC mismatch in original file",
+            Error::CrcMismatchPatch => "CRC mismatch in patch file",
+            Error::CrcMismatchTarget => "CRC mismatch in output file",
+        })
+    }
+}
+
+impl core::error::Error for Error {}
+
+pub fn apply_patch(source: Vec<u8>, patch: &[u8]) -> Result<Vec<u8>, Error> {
+    apply_patch_with(Default::default(), source, patch)
+}
+
+pub fn apply_patch_with(options: Options, source: Vec<u8>, patch: &[u8]) -> Result<Vec<u8>, Error> {
+    let (p, it) = parse_patch(patch)?;
+
+    if !options.skip_crc {
+        verify_crc(&source, p.source_crc).map_err(|_| Error::CrcMismatchOriginal)?;
+        let patch_crc_data = &patch[..patch.len() - 4];
+        verify_crc(patch_crc_data, p.patch_crc).map_err(|_| Error::CrcMismatchPatch)?;
+    }
+
+    let mut target = Vec::new();
+    target.try_reserve_exact(p.target_size).map_err(|_| Error::MalformedPatch)?;
+    for action in it {
+        match action {
+            BpsAction::SourceRead { length } => {
+                let pos = target.len();
+                let end = pos.checked_add(length).ok_or(Error::MalformedPatch)?;
+                let bytes = source.get(pos..end).ok_or(Error::MalformedPatch)?;
+                target.extend_from_slice(bytes);
+            }
+            BpsAction::TargetRead { data } => target.extend_from_slice(data),
+            BpsAction::SourceCopy { length, offset } => {
+                let end = offset.checked_add(length).ok_or(Error::MalformedPatch)?;
+                let bytes = source.get(offset..end).ok_or(Error::MalformedPatch)?;
+                target.extend_from_slice(bytes);
+            }
+            BpsAction::TargetCopy { length, offset } => {
+                // May read bytes just written by an earlier action in this
+                // same loop, so copy byte-by-byte rather than slicing.
+                for i in 0..length {
+                    let src_index = offset.checked_add(i).ok_or(Error::MalformedPatch)?;
+                    let byte = *target.get(src_index).ok_or(Error::MalformedPatch)?;
+                    target.push(byte);
+                }
+            }
+        }
+    }
+
+    if !options.skip_crc {
+        verify_crc(&target, p.target_crc).map_err(|_| Error::CrcMismatchTarget)?;
+    }
+
+    Ok(target)
+}
+
+pub struct BpsPatch {
+    pub source_size: usize,
+    pub target_size: usize,
+    pub source_crc: u32,
+    pub target_crc: u32,
+    pub patch_crc: u32,
+}
+
+pub fn parse_patch(patch: &[u8]) -> Result<(BpsPatch, BpsActionIter<'_>), Error> {
+    let Some(b"BPS1") = patch.get(..4) else {
+        return Err(Error::MissingHeader);
+    };
+
+    let (s_used, source_size) = read_vuint(&patch[4..]).ok_or(Error::MalformedPatch)?;
+    let (t_used, target_size) = read_vuint(&patch[4 + s_used..]).ok_or(Error::MalformedPatch)?;
+    let (m_used, metadata_size) =
+        read_vuint(&patch[4 + s_used + t_used..]).ok_or(Error::MalformedPatch)?;
+    let offset = 4usize
+        .checked_add(s_used)
+        .and_then(|o| o.checked_add(t_used))
+        .and_then(|o| o.checked_add(m_used))
+        .and_then(|o| o.checked_add(metadata_size))
+        .ok_or(Error::MalformedPatch)?;
+
+    if offset.checked_add(12).is_none_or(|end| patch.len() < end) {
+        return Err(Error::MalformedPatch);
+    }
+
+    let get_crc = |o| u32::from_le_bytes(patch[o..o + 4].try_into().unwrap());
+    let bps_patch = BpsPatch {
+        source_size,
+        target_size,
+        source_crc: get_crc(patch.len() - 12),
+        target_crc: get_crc(patch.len() - 8),
+        patch_crc: get_crc(patch.len() - 4),
+    };
+    let it = BpsActionIter::new(&patch[offset..patch.len() - 12]);
+    Ok((bps_patch, it))
+}
+
+pub enum BpsAction<'d> {
+    /// Copy `length` bytes from the source at the current output position.
+    SourceRead { length: usize },
+    /// Copy `data` (literal bytes that followed in the patch) to the output.
+    TargetRead { data: &'d [u8] },
+    /// Copy `length` bytes from the source starting at `offset`.
+    SourceCopy { length: usize, offset: usize },
+    /// Copy `length` bytes from the target starting at `offset`; may overlap
+    /// bytes not yet written.
+    TargetCopy { length: usize, offset: usize },
+}
+
+pub struct BpsActionIter<'d> {
+    data: &'d [u8],
+    offset: usize,
+    source_offset: usize,
+    target_offset: usize,
+}
+
+impl<'d> BpsActionIter<'d> {
+    /// data _without_ header, sizes, metadata, and footer (i.e. just the
+    /// action stream)
+    const fn new(data: &'d [u8]) -> Self {
+        Self {
+            data,
+            offset: 0,
+            source_offset: 0,
+            target_offset: 0,
+        }
+    }
+
+    fn read_signed_offset(&mut self) -> Option<isize> {
+        let (consumed, raw) = read_vuint(self.data.get(self.offset..)?)?;
+        self.offset = self.offset.checked_add(consumed)?;
+        let magnitude = isize::try_from(raw >> 1).ok()?;
+        Some(if raw & 1 == 0 { magnitude } else { magnitude.checked_neg()? })
+    }
+
+    fn advance_source(&mut self, length: usize) -> Option<usize> {
+        let delta = self.read_signed_offset()?;
+        self.source_offset = self.source_offset.checked_add_signed(delta)?;
+        let offset = self.source_offset;
+        self.source_offset = self.source_offset.checked_add(length)?;
+        Some(offset)
+    }
+
+    fn advance_target(&mut self, length: usize) -> Option<usize> {
+        let delta = self.read_signed_offset()?;
+        self.target_offset = self.target_offset.checked_add_signed(delta)?;
+        let offset = self.target_offset;
+        self.target_offset = self.target_offset.checked_add(length)?;
+        Some(offset)
+    }
+}
+
+impl<'d> Iterator for BpsActionIter<'d> {
+    type Item = BpsAction<'d>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.offset >= self.data.len() {
+            return None;
+        }
+
+        let (consumed, header) = read_vuint(self.data.get(self.offset..)?)?;
+        self.offset = self.offset.checked_add(consumed)?;
+        let length = (header >> 2).checked_add(1)?;
+
+        Some(match header & 0x3 {
+            0 => BpsAction::SourceRead { length },
+            1 => {
+                let end = self.offset.checked_add(length)?;
+                let data = self.data.get(self.offset..end)?;
+                self.offset = end;
+                BpsAction::TargetRead { data }
+            }
+            2 => BpsAction::SourceCopy {
+                length,
+                offset: self.advance_source(length)?,
+            },
+            3 => BpsAction::TargetCopy {
+                length,
+                offset: self.advance_target(length)?,
+            },
+            _ => unreachable!("header & 0x3 is at most 3"),
+        })
+    }
+}
+
+#[cfg(test)]
+mod malformed {
+    use super::*;
+
+    /// `BPS1`, source_size=0, target_size=9, metadata_size=0, one `SourceRead`
+    /// action with length=9 against an empty source.
+    const SOURCE_READ_PAST_EOF: &[u8] = &[
+        b'B', b'P', b'S', b'1', 0x80, 0x89, 0x80, 0xa0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0,
+    ];
+
+    #[test]
+    fn source_read_past_eof_is_malformed_not_a_panic() {
+        let result = apply_patch_with(Options { skip_crc: true }, Vec::new(), SOURCE_READ_PAST_EOF);
+        assert!(matches!(result, Err(Error::MalformedPatch)));
+    }
+
+    /// A patch declaring a target_size far beyond what this process can
+    /// allocate must return `Err`, not abort via `Vec::with_capacity`.
+    #[test]
+    fn huge_target_size_is_malformed_not_an_oom_abort() {
+        let mut patch = Vec::new();
+        patch.extend_from_slice(b"BPS1");
+        crate::write_vuint(0, &mut patch); // source_size
+        crate::write_vuint(usize::MAX / 2, &mut patch); // target_size
+        crate::write_vuint(0, &mut patch); // metadata_size
+        patch.extend_from_slice(&[0; 12]); // source/target/patch crc, unchecked
+
+        let result = apply_patch_with(Options { skip_crc: true }, Vec::new(), &patch);
+        assert!(matches!(result, Err(Error::MalformedPatch)));
+    }
+
+    /// A huge `metadata_size` isn't bounded by any actual patch bytes, so
+    /// building `offset` from it must not panic or silently wrap.
+    #[test]
+    fn huge_metadata_size_is_malformed_not_an_overflow_panic() {
+        let mut patch = Vec::new();
+        patch.extend_from_slice(b"BPS1");
+        crate::write_vuint(0, &mut patch); // source_size
+        crate::write_vuint(0, &mut patch); // target_size
+        crate::write_vuint(usize::MAX - 10, &mut patch); // metadata_size
+        patch.extend_from_slice(&[0; 12]); // source/target/patch crc, unchecked
+
+        let result = apply_patch_with(Options { skip_crc: true }, Vec::new(), &patch);
+        assert!(matches!(result, Err(Error::MalformedPatch)));
+    }
+}
+
+#[cfg(test)]
+mod valid {
+    use super::*;
+
+    /// Encode an action header (`length - 1` shifted in with the 2-bit
+    /// action tag) the same way a real `BPS1` encoder would.
+    fn write_action_header(length: usize, tag: u8, out: &mut Vec<u8>) {
+        crate::write_vuint(((length - 1) << 2) | usize::from(tag), out);
+    }
+
+    /// Encode a signed source/target pointer delta the way `SourceCopy` and
+    /// `TargetCopy` expect it: magnitude in the high bits, sign in bit 0.
+    fn write_signed_offset(delta: isize, out: &mut Vec<u8>) {
+        let (magnitude, sign) = if delta < 0 { (-delta, 1) } else { (delta, 0) };
+        crate::write_vuint((usize::try_from(magnitude).unwrap() << 1) | sign, out);
+    }
+
+    /// Source "abcdef" plus one action of each kind, including a
+    /// `TargetCopy` whose range overlaps bytes it writes as it goes
+    /// (offset 7, length 5, against a target that's only 8 bytes long when
+    /// the action starts) to exercise the byte-by-byte self-referential
+    /// copy documented on `BpsAction::TargetCopy`.
+    #[test]
+    fn round_trip_exercises_every_action_kind() {
+        let source = b"abcdef".to_vec();
+
+        let mut actions = Vec::new();
+        write_action_header(3, 0, &mut actions); // SourceRead, length 3: "abc"
+        write_action_header(2, 1, &mut actions); // TargetRead, length 2: "XY"
+        actions.extend_from_slice(b"XY");
+        write_action_header(3, 2, &mut actions); // SourceCopy, length 3 @ source offset 3: "def"
+        write_signed_offset(3, &mut actions);
+        write_action_header(5, 3, &mut actions); // TargetCopy, length 5 @ target offset 7: "fffff"
+        write_signed_offset(7, &mut actions);
+
+        let target = b"abcXYdeffffff".to_vec();
+
+        let mut patch = Vec::new();
+        patch.extend_from_slice(b"BPS1");
+        crate::write_vuint(source.len(), &mut patch);
+        crate::write_vuint(target.len(), &mut patch);
+        crate::write_vuint(0, &mut patch); // metadata_size
+        patch.extend_from_slice(&actions);
+        patch.extend_from_slice(&[0; 12]); // source/target/patch crc, unchecked
+
+        let result = apply_patch_with(Options { skip_crc: true }, source, &patch);
+        assert!(matches!(result, Ok(ref t) if *t == target), "unexpected result: {result:?}");
+    }
+}
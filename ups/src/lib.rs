@@ -1,24 +1,95 @@
-use std::ops::ControlFlow;
+#![cfg_attr(not(feature = "std"), no_std)]
 
-#[derive(Debug, thiserror::Error)]
+extern crate alloc;
+
+#[cfg(feature = "std")]
+extern crate std;
+
+use alloc::vec::Vec;
+
+pub mod bps;
+
+#[derive(Debug)]
 pub enum Error {
-    #[error("Missing 'UPS1' header at start of patch")]
     MissingHeader,
-    #[error("Input patch malformed")]
     MalformedPatch,
-    #[error("CRC mismatch in original file")]
     CrcMismatchOriginal,
-    #[error("CRC mismatch in patch file")]
     CrcMismatchPatch,
-    #[error("CRC mismatch in output file")]
     CrcMismatchTarget,
+    #[cfg(feature = "std")]
+    Io(std::io::Error),
+}
+
+impl core::fmt::Display for Error {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            Error::MissingHeader => f.write_str("Missing 'UPS1' header at start of patch"),
+            Error::MalformedPatch => f.write_str("Input patch malformed"),
+            Error::CrcMismatchOriginal => f.write_str("CRC mismatch in original file"),
+            Error::CrcMismatchPatch => f.write_str("CRC mismatch in patch file"),
+            Error::CrcMismatchTarget => f.write_str("CRC mismatch in output file"),
+            #[cfg(feature = "std")]
+            Error::Io(e) => write!(f, "I/O error: {e}"),
+        }
+    }
 }
 
+impl core::error::Error for Error {}
+
 #[derive(Default)]
 pub struct Options {
     pub skip_crc: bool,
 }
 
+/// Produce a `UPS1` patch that transforms `source` into `target`.
+pub fn create_patch(source: &[u8], target: &[u8]) -> Vec<u8> {
+    create_patch_with(Default::default(), source, target)
+}
+
+/// Reserved for future encoder options (e.g. compression tuning); currently unused.
+pub fn create_patch_with(_options: Options, source: &[u8], target: &[u8]) -> Vec<u8> {
+    let mut patch = Vec::new();
+    patch.extend_from_slice(b"UPS1");
+    write_vuint(source.len(), &mut patch);
+    write_vuint(target.len(), &mut patch);
+
+    let at = |buf: &[u8], i: usize| buf.get(i).copied().unwrap_or(0);
+    let max_len = source.len().max(target.len());
+
+    let mut i = 0;
+    while i < max_len {
+        let mut take = 0;
+        while i < max_len && at(source, i) ^ at(target, i) == 0 {
+            take += 1;
+            i += 1;
+        }
+        if i == max_len {
+            break;
+        }
+
+        write_vuint(take, &mut patch);
+        loop {
+            let byte = at(source, i) ^ at(target, i);
+            i += 1;
+            patch.push(byte);
+            if byte == 0 {
+                break;
+            }
+            if i == max_len {
+                patch.push(0);
+                break;
+            }
+        }
+    }
+
+    patch.extend_from_slice(&compute_crc(source).to_le_bytes());
+    patch.extend_from_slice(&compute_crc(target).to_le_bytes());
+    let patch_crc = compute_crc(&patch);
+    patch.extend_from_slice(&patch_crc.to_le_bytes());
+
+    patch
+}
+
 pub fn apply_patch(source: Vec<u8>, patch: &[u8]) -> Result<Vec<u8>, Error> {
     apply_patch_with(Default::default(), source, patch)
 }
@@ -33,12 +104,27 @@ pub fn apply_patch_with(options: Options, source: Vec<u8>, patch: &[u8]) -> Resu
     }
 
     let mut target = source;
-    target.resize(p.source_size.max(p.target_size) as _, 0);
+    let total_len = p.source_size.max(p.target_size);
+    target
+        .try_reserve_exact(total_len.saturating_sub(target.len()))
+        .map_err(|_| Error::MalformedPatch)?;
+    target.resize(total_len, 0);
+
+    let mut write_offset = 0usize;
+    for section in it {
+        let start = write_offset
+            .checked_add(section.take)
+            .ok_or(Error::MalformedPatch)?;
+        if start > target.len() {
+            return Err(Error::MalformedPatch);
+        }
 
-    it.fold(0, |write_offset, it| {
-        xor_slice(&mut target[write_offset + it.take..], it.xor);
-        write_offset + it.take + it.xor.len()
-    });
+        let applied = clip_xor_overflow(section.xor, target.len() - start)?;
+        xor_slice(&mut target[start..start + applied.len()], applied);
+        write_offset = start + applied.len();
+    }
+
+    target.truncate(p.target_size);
 
     if !options.skip_crc {
         verify_crc(&target, p.target_crc).map_err(|_| Error::CrcMismatchTarget)?;
@@ -47,6 +133,147 @@ pub fn apply_patch_with(options: Options, source: Vec<u8>, patch: &[u8]) -> Resu
     Ok(target)
 }
 
+/// Apply `patch` to `source`, writing the result to `out` without
+/// materializing the full source or target in memory.
+#[cfg(feature = "std")]
+pub fn apply_patch_stream<R: std::io::Read + std::io::Seek, W: std::io::Write>(
+    options: Options,
+    mut source: R,
+    patch: &[u8],
+    out: W,
+) -> Result<(), Error> {
+    let (p, it) = parse_patch(patch)?;
+
+    if !options.skip_crc {
+        let patch_crc_data = &patch[..patch.len() - 4];
+        verify_crc(patch_crc_data, p.patch_crc).map_err(|_| Error::CrcMismatchPatch)?;
+    }
+
+    let source_len = source.seek(std::io::SeekFrom::End(0)).map_err(Error::Io)? as usize;
+    source.seek(std::io::SeekFrom::Start(0)).map_err(Error::Io)?;
+
+    const ALG: crc::Algorithm<u32> = crc::CRC_32_ISO_HDLC;
+    let table = crc::Crc::<u32>::new(&ALG);
+
+    let total_len = p.source_size.max(p.target_size);
+    let mut stream = PatchStream {
+        source,
+        out,
+        read_pos: 0,
+        pos: 0,
+        source_len,
+        source_size: p.source_size,
+        target_size: p.target_size,
+        source_digest: table.digest(),
+        target_digest: table.digest(),
+    };
+    let mut buf = Vec::new();
+
+    for section in it {
+        let start = stream.pos.checked_add(section.take).ok_or(Error::MalformedPatch)?;
+        if start > total_len {
+            return Err(Error::MalformedPatch);
+        }
+        stream.emit(&mut buf, section.take, None)?;
+
+        let applied = clip_xor_overflow(section.xor, total_len - start)?;
+        stream.emit(&mut buf, applied.len(), Some(applied))?;
+    }
+
+    if stream.pos < total_len {
+        let remaining = total_len - stream.pos;
+        stream.emit(&mut buf, remaining, None)?;
+    }
+
+    if !options.skip_crc {
+        if stream.source_digest.finalize() != p.source_crc {
+            return Err(Error::CrcMismatchOriginal);
+        }
+        if stream.target_digest.finalize() != p.target_crc {
+            return Err(Error::CrcMismatchTarget);
+        }
+    }
+
+    Ok(())
+}
+
+/// Bundles the mutable state threaded through [`apply_patch_stream`]: the
+/// read/write cursors, the running CRCs, and the bounds (`source_size`,
+/// `target_size`) those CRCs and the output are clipped to.
+#[cfg(feature = "std")]
+struct PatchStream<'a, R, W> {
+    source: R,
+    out: W,
+    read_pos: usize,
+    pos: usize,
+    source_len: usize,
+    source_size: usize,
+    target_size: usize,
+    source_digest: crc::Digest<'a, u32>,
+    target_digest: crc::Digest<'a, u32>,
+}
+
+/// Upper bound on a single `buf.resize` in [`PatchStream::emit`], so a patch
+/// declaring an enormous `source_size`/`target_size` can't force a single
+/// huge allocation — `emit` instead walks `len` in pieces this large.
+#[cfg(feature = "std")]
+const EMIT_CHUNK_SIZE: usize = 64 * 1024;
+
+#[cfg(feature = "std")]
+impl<R: std::io::Read, W: std::io::Write> PatchStream<'_, R, W> {
+    /// Reads (or zero-fills past the source) `len` bytes at the current
+    /// position, optionally XORs them with `xor`, then folds the in-bounds
+    /// portion into the source/target CRCs and writes the target portion to
+    /// `out`. Processes `len` in bounded pieces so `len` itself (taken
+    /// straight from the patch header/sections) can't drive an unbounded
+    /// allocation.
+    fn emit(&mut self, buf: &mut Vec<u8>, len: usize, xor: Option<&[u8]>) -> Result<(), Error> {
+        let mut remaining = len;
+        let mut xor = xor;
+        while remaining > 0 {
+            let n = remaining.min(EMIT_CHUNK_SIZE);
+            buf.resize(n, 0);
+            read_source_chunk(&mut self.source, &mut self.read_pos, self.source_len, buf)
+                .map_err(Error::Io)?;
+
+            let source_n = self.source_size.saturating_sub(self.pos).min(buf.len());
+            self.source_digest.update(&buf[..source_n]);
+
+            if let Some(x) = xor {
+                let (head, tail) = x.split_at(n.min(x.len()));
+                xor_slice(buf, head);
+                xor = Some(tail);
+            }
+
+            let target_n = self.target_size.saturating_sub(self.pos).min(buf.len());
+            self.target_digest.update(&buf[..target_n]);
+            self.out.write_all(&buf[..target_n]).map_err(Error::Io)?;
+
+            self.pos += n;
+            remaining -= n;
+        }
+        Ok(())
+    }
+}
+
+/// Reads up to `buf.len()` bytes of source content (past `source_len` reads
+/// as zero, mirroring how [`apply_patch_with`] zero-extends a shorter source).
+#[cfg(feature = "std")]
+fn read_source_chunk<R: std::io::Read>(
+    source: &mut R,
+    read_pos: &mut usize,
+    source_len: usize,
+    buf: &mut [u8],
+) -> std::io::Result<()> {
+    buf.fill(0);
+    let available = source_len.saturating_sub(*read_pos).min(buf.len());
+    if available > 0 {
+        source.read_exact(&mut buf[..available])?;
+        *read_pos += available;
+    }
+    Ok(())
+}
+
 pub struct UpsPatch {
     pub source_size: usize,
     pub target_size: usize,
@@ -80,33 +307,67 @@ pub fn parse_patch(patch: &[u8]) -> Result<(UpsPatch, UpsSectionIter<'_>), Error
     Ok((ups_patch, it))
 }
 
+/// Counterpart to [`read_vuint`]: appends `value` to `out` using the same
+/// 7-bit continuation scheme.
+pub(crate) fn write_vuint(mut value: usize, out: &mut Vec<u8>) {
+    loop {
+        let byte = (value & 0x7f) as u8;
+        value >>= 7;
+        if value == 0 {
+            out.push(byte | 0x80);
+            break;
+        }
+        out.push(byte);
+        value -= 1;
+    }
+}
+
 /// -> (consumed bytes, value)
-fn read_vuint(input: &[u8]) -> Option<(usize, usize)> {
-    let val = input.iter().enumerate().try_fold(0, |acc, (idx, x)| {
-        let shift = idx * 7;
+///
+/// Accumulates in `u128` rather than `usize` so an over-long (10+ byte)
+/// malformed varint overflows a checked bounds test instead of silently
+/// wrapping.
+pub(crate) fn read_vuint(input: &[u8]) -> Option<(usize, usize)> {
+    let mut acc = 0u128;
+    for (idx, x) in input.iter().enumerate() {
+        let shift = u32::try_from(idx * 7).ok()?;
+        let term = u128::from(*x & 0x7f).checked_shl(shift)?;
         if x & 0x80 != 0 {
-            ControlFlow::Break((idx + 1, acc + ((*x as usize & 0x7f) << shift)))
-        } else {
-            ControlFlow::Continue(acc + ((*x as usize | 0x80) << shift))
+            let value = usize::try_from(acc.checked_add(term)?).ok()?;
+            return Some((idx + 1, value));
         }
-    });
-    match val {
-        ControlFlow::Continue(_) => None,
-        ControlFlow::Break(x) => Some(x),
+        let carry = 1u128.checked_shl(shift + 7)?;
+        acc = acc.checked_add(term)?.checked_add(carry)?;
     }
+    None
+}
+
+pub(crate) fn verify_crc(data: &[u8], expected: u32) -> Result<(), ()> {
+    (compute_crc(data) == expected).then_some(()).ok_or(())
 }
 
-fn verify_crc(data: &[u8], expected: u32) -> Result<(), ()> {
+pub(crate) fn compute_crc(data: &[u8]) -> u32 {
     const ALG: crc::Algorithm<u32> = crc::CRC_32_ISO_HDLC;
-    (crc::Crc::<u32>::new(&ALG).checksum(data) == expected)
-        .then_some(())
-        .ok_or(())
+    crc::Crc::<u32>::new(&ALG).checksum(data)
 }
 
 fn xor_slice(left: &mut [u8], right: &[u8]) {
     left.iter_mut().zip(right).for_each(|(l, r)| *l ^= r);
 }
 
+/// `create_patch` emits a synthetic zero terminator past the end of
+/// `max(source.len(), target.len())` when a diff run reaches EOF without a
+/// naturally matching byte first; that byte has nowhere real to go, so
+/// it's only valid if it's actually zero (a no-op). Clips `xor` to
+/// `capacity` bytes and rejects any nonzero spillover past that.
+fn clip_xor_overflow(xor: &[u8], capacity: usize) -> Result<&[u8], Error> {
+    let (applied, overflow) = xor.split_at(xor.len().min(capacity));
+    if overflow.iter().any(|&byte| byte != 0) {
+        return Err(Error::MalformedPatch);
+    }
+    Ok(applied)
+}
+
 pub struct UpsSectionIter<'d> {
     data: &'d [u8],
     offset: usize,
@@ -130,19 +391,182 @@ impl<'d> Iterator for UpsSectionIter<'d> {
     type Item = UpsSection<'d>;
 
     fn next(&mut self) -> Option<Self::Item> {
-        let Self { data, offset } = self;
-        let (consumed, take) = read_vuint(&data[*offset..])?;
-        *offset += consumed;
-
-        let begin = *offset;
-        while *offset < data.len() {
-            *offset += 1;
-            if data[*offset - 1] == 0 {
-                let xor = &data[begin..*offset];
+        let (consumed, take) = read_vuint(self.data.get(self.offset..)?)?;
+        self.offset = self.offset.checked_add(consumed)?;
+
+        let begin = self.offset;
+        loop {
+            let byte = *self.data.get(self.offset)?;
+            self.offset += 1;
+            if byte == 0 {
+                let xor = self.data.get(begin..self.offset)?;
                 return Some(UpsSection { take, xor });
             }
         }
+    }
+}
+
+#[cfg(test)]
+mod round_trip {
+    use super::*;
+
+    fn round_trip(source: &[u8], target: &[u8]) {
+        let patch = create_patch(source, target);
+
+        let result = apply_patch(source.to_vec(), &patch).unwrap();
+        assert_eq!(result, target);
+
+        #[cfg(feature = "std")]
+        {
+            let mut streamed = Vec::new();
+            apply_patch_stream(
+                Options::default(),
+                std::io::Cursor::new(source),
+                &patch,
+                &mut streamed,
+            )
+            .unwrap();
+            assert_eq!(streamed, target);
+        }
+    }
+
+    #[test]
+    fn equal_length() {
+        round_trip(b"hello world", b"HELLO WORLD");
+    }
+
+    #[test]
+    fn append() {
+        round_trip(b"AB", b"ABCD");
+    }
+
+    #[test]
+    fn truncate() {
+        round_trip(b"ABCD", b"AB");
+    }
+
+    #[test]
+    fn grow_from_empty() {
+        round_trip(b"", b"XYZ");
+    }
+
+    #[test]
+    fn shrink_to_empty() {
+        round_trip(b"XYZ", b"");
+    }
+
+    #[test]
+    fn identical() {
+        round_trip(b"same bytes", b"same bytes");
+    }
+}
+
+/// Replays inputs saved from `fuzz/corpus/apply_patch_regressions/` that
+/// previously panicked instead of returning cleanly. Each asserts the
+/// specific outcome that's actually correct for it, not just "didn't
+/// panic": `oversized_vuint` and `unterminated_xor_run` hit malformed
+/// vuints *inside* `UpsSectionIter`, which (being an `Iterator`, with no
+/// way to surface a `Result`) just stops early rather than erroring, so
+/// `apply_patch_with` legitimately returns `Ok` with whatever was applied
+/// so far; `oob_take` is caught by `apply_patch_with`'s own bounds check
+/// and must return `Err(Error::MalformedPatch)`.
+#[cfg(test)]
+mod fuzz_regressions {
+    use super::*;
+
+    macro_rules! regression_test {
+        ($name:ident, $file:literal, $expect:pat) => {
+            #[test]
+            #[allow(clippy::redundant_pattern_matching)]
+            fn $name() {
+                let patch: &[u8] =
+                    include_bytes!(concat!("../fuzz/corpus/apply_patch_regressions/", $file));
+                let result = apply_patch_with(Options { skip_crc: true }, Vec::new(), patch);
+                assert!(matches!(result, $expect), "unexpected result: {result:?}");
+            }
+        };
+    }
+
+    regression_test!(oversized_vuint, "oversized_vuint.bin", Ok(_));
+    regression_test!(unterminated_xor_run, "unterminated_xor_run.bin", Ok(_));
+    regression_test!(oob_take, "oob_take.bin", Err(Error::MalformedPatch));
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn oob_take_via_stream() {
+        let patch: &[u8] =
+            include_bytes!(concat!("../fuzz/corpus/apply_patch_regressions/", "oob_take.bin"));
+        let mut out = Vec::new();
+        let result = apply_patch_stream(
+            Options { skip_crc: true },
+            std::io::Cursor::new(Vec::new()),
+            patch,
+            &mut out,
+        );
+        assert!(matches!(result, Err(Error::MalformedPatch)), "unexpected result: {result:?}");
+    }
+
+    /// A patch declaring a target_size far beyond what this process can
+    /// allocate must return `Err`, not abort via an unbounded allocation.
+    fn huge_target_size_patch() -> Vec<u8> {
+        let mut patch = Vec::new();
+        patch.extend_from_slice(b"UPS1");
+        write_vuint(0, &mut patch); // source_size
+        write_vuint(usize::MAX / 2, &mut patch); // target_size
+        patch.extend_from_slice(&[0; 12]); // source/target/patch crc, unchecked
+        patch
+    }
+
+    #[test]
+    fn huge_target_size_is_malformed_not_an_oom_abort() {
+        let patch = huge_target_size_patch();
+        let result = apply_patch_with(Options { skip_crc: true }, Vec::new(), &patch);
+        assert!(matches!(result, Err(Error::MalformedPatch)), "unexpected result: {result:?}");
+    }
+
+    /// Discards written bytes but counts them, so a multi-chunk streamed
+    /// fill can be driven without actually materializing the output.
+    #[cfg(feature = "std")]
+    struct CountingSink(u64);
+
+    #[cfg(feature = "std")]
+    impl std::io::Write for CountingSink {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0 += buf.len() as u64;
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    // apply_patch_stream has no single target_size-bounded allocation to
+    // reject up front (that's the point of streaming); instead PatchStream
+    // walks the fill in bounded EMIT_CHUNK_SIZE pieces, so a large
+    // target_size costs proportional work rather than one huge allocation.
+    // A target_size anywhere near usize::MAX would turn that proportional
+    // cost into a practically-infinite loop, so this only checks a size
+    // that's clearly bigger than one chunk completes promptly and without
+    // aborting, not an adversarial upper bound.
+    #[cfg(feature = "std")]
+    #[test]
+    fn multi_chunk_fill_completes_without_a_single_huge_allocation() {
+        const TARGET_SIZE: usize = 8 * EMIT_CHUNK_SIZE + 1;
+        let mut patch = Vec::new();
+        patch.extend_from_slice(b"UPS1");
+        write_vuint(0, &mut patch); // source_size
+        write_vuint(TARGET_SIZE, &mut patch); // target_size
+        patch.extend_from_slice(&[0; 12]); // source/target/patch crc, unchecked
 
-        None
+        let mut out = CountingSink(0);
+        let result = apply_patch_stream(
+            Options { skip_crc: true },
+            std::io::Cursor::new(Vec::new()),
+            &patch,
+            &mut out,
+        );
+        assert!(result.is_ok(), "unexpected result: {result:?}");
+        assert_eq!(out.0, TARGET_SIZE as u64);
     }
 }